@@ -0,0 +1,209 @@
+//! A small caching stub resolver that the daemon binds to a loopback address and points the
+//! OS at, instead of handing it the tunnel's upstream servers directly. This gives DNS-leak
+//! protection and response caching without relying on the OS resolver's own behavior, and is a
+//! choke point for future encrypted-DNS support.
+//!
+//! Currently only [`super::windows`] starts and points the OS at this resolver. Linux is left
+//! alone because `systemd-resolved` already owns `127.0.0.53` and provides its own caching and
+//! DoT/DNSSEC enforcement (see `talpid_dbus::systemd_resolved`); running this resolver there
+//! too would just be a second loopback server fighting the first for port 53.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use trust_dns_client::rr::{Record, RecordType};
+use trust_dns_proto::rr::Name;
+use trust_dns_resolver::{
+    config::{
+        LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+    },
+    TokioAsyncResolver,
+};
+use trust_dns_server::{
+    authority::MessageResponseBuilder,
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    ServerFuture,
+};
+
+/// Loopback address that the stub resolver listens on, analogous to how systemd-resolved
+/// listens on `127.0.0.53`.
+pub const LISTEN_ADDRS: &[IpAddr] = &[
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53)),
+    IpAddr::V6(Ipv6Addr::LOCALHOST),
+];
+
+const LISTEN_PORT: u16 = 53;
+const NUM_RETRY_ATTEMPTS: usize = 2;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors that can happen when starting or reconfiguring the stub resolver.
+#[derive(err_derive::Error, Debug)]
+#[error(no_from)]
+pub enum Error {
+    #[error(display = "Failed to bind the stub resolver to a loopback address")]
+    Bind(#[error(source)] io::Error),
+
+    #[error(display = "Failed to construct the upstream resolver")]
+    CreateResolver(#[error(source)] trust_dns_resolver::error::ResolveError),
+}
+
+/// Handle to a running stub resolver. Dropping this shuts the resolver down.
+pub struct ResolverHandle {
+    server: ServerFuture<StubHandler>,
+    inner: Arc<StubHandler>,
+}
+
+impl ResolverHandle {
+    /// Replaces the set of upstream servers that queries are forwarded to, e.g. when the
+    /// daemon switches to a new relay. This rebuilds the resolver, so the cache is dropped along
+    /// with the old upstreams - answers cached via one relay (CDN/geo-routing results in
+    /// particular) aren't necessarily valid via another.
+    pub fn set_upstreams(&self, upstreams: Vec<IpAddr>) -> Result<(), Error> {
+        self.inner.set_upstreams(upstreams)
+    }
+
+    /// The loopback addresses this resolver is listening on. Callers should point the OS
+    /// resolver configuration (`SetDNS`, `NameServer`, ...) at these instead of the real
+    /// upstream servers.
+    pub fn listen_addrs(&self) -> &'static [IpAddr] {
+        LISTEN_ADDRS
+    }
+
+    pub async fn shutdown(mut self) {
+        let _ = self.server.shutdown_gracefully().await;
+    }
+}
+
+/// Starts the stub resolver, forwarding queries to `upstreams` over the tunnel.
+pub async fn start(upstreams: Vec<IpAddr>, ipv6_first: bool) -> Result<ResolverHandle, Error> {
+    let inner = Arc::new(StubHandler::new(upstreams, ipv6_first)?);
+
+    let mut server = ServerFuture::new(inner.clone());
+    for addr in LISTEN_ADDRS {
+        let socket = tokio::net::UdpSocket::bind(SocketAddr::new(*addr, LISTEN_PORT))
+            .await
+            .map_err(Error::Bind)?;
+        server.register_socket(socket);
+    }
+
+    Ok(ResolverHandle { server, inner })
+}
+
+/// Forwards recursive-desired A/AAAA/PTR/TXT queries to the configured upstream servers and
+/// caches responses until their TTL expires. Everything else (zone transfers, obsolete record
+/// types) is refused so that well-behaved clients fall back to retrying against the real
+/// servers.
+struct StubHandler {
+    resolver: RwLock<TokioAsyncResolver>,
+}
+
+impl StubHandler {
+    fn new(upstreams: Vec<IpAddr>, ipv6_first: bool) -> Result<Self, Error> {
+        Ok(StubHandler {
+            resolver: RwLock::new(Self::build_resolver(upstreams, ipv6_first)?),
+        })
+    }
+
+    fn build_resolver(
+        upstreams: Vec<IpAddr>,
+        ipv6_first: bool,
+    ) -> Result<TokioAsyncResolver, Error> {
+        let servers = NameServerConfigGroup::from_ips_clear(&upstreams, 53, true);
+        let config = ResolverConfig::from_parts(None, vec![], servers);
+
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = if ipv6_first {
+            LookupIpStrategy::Ipv6thenIpv4
+        } else {
+            LookupIpStrategy::Ipv4AndIpv6
+        };
+        opts.attempts = NUM_RETRY_ATTEMPTS;
+        opts.timeout = QUERY_TIMEOUT;
+        opts.cache_size = 256;
+
+        TokioAsyncResolver::tokio(config, opts).map_err(Error::CreateResolver)
+    }
+
+    fn set_upstreams(&self, upstreams: Vec<IpAddr>) -> Result<(), Error> {
+        let ipv6_first = matches!(
+            self.resolver.read().unwrap().options().ip_strategy,
+            LookupIpStrategy::Ipv6thenIpv4
+        );
+        *self.resolver.write().unwrap() = Self::build_resolver(upstreams, ipv6_first)?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RequestHandler for StubHandler {
+    async fn handle_request<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let query = request.query();
+
+        if !request.header().recursion_desired() {
+            return refuse(request, &mut response_handle).await;
+        }
+
+        let record_type = query.query_type();
+        if !matches!(
+            record_type,
+            RecordType::A | RecordType::AAAA | RecordType::PTR | RecordType::TXT
+        ) {
+            return refuse(request, &mut response_handle).await;
+        }
+
+        let name: Name = query.name().into();
+        let resolver = self.resolver.read().unwrap().clone();
+        let (records, response_code) = match resolver.lookup(name.clone(), record_type).await {
+            Ok(lookup) => (
+                lookup.record_iter().cloned().collect::<Vec<_>>(),
+                trust_dns_proto::op::ResponseCode::NoError,
+            ),
+            // No records for this name/type is a legitimate, cacheable answer - distinct from
+            // the upstream lookup itself failing (timeout, SERVFAIL, ...), which we surface to
+            // the client as a failure instead of a silent empty NOERROR so it can retry instead
+            // of treating a transient problem as "this name has no records".
+            Err(error) if error.is_no_records_found() => {
+                (Vec::new(), trust_dns_proto::op::ResponseCode::NoError)
+            }
+            Err(error) => {
+                log::debug!("Upstream lookup for {} failed: {}", name, error);
+                (Vec::new(), trust_dns_proto::op::ResponseCode::ServFail)
+            }
+        };
+
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut header = *request.header();
+        header.set_recursion_available(true);
+        header.set_response_code(response_code);
+        let response = builder.build(
+            header,
+            records.iter(),
+            std::iter::empty::<&Record>(),
+            std::iter::empty::<&Record>(),
+            std::iter::empty::<&Record>(),
+        );
+        response_handle
+            .send_response(response)
+            .await
+            .unwrap_or_else(|_| ResponseInfo::from(*request.header()))
+    }
+}
+
+async fn refuse<R: ResponseHandler>(request: &Request, response_handle: &mut R) -> ResponseInfo {
+    let builder = MessageResponseBuilder::from_message_request(request);
+    let mut header = *request.header();
+    header.set_response_code(trust_dns_proto::op::ResponseCode::Refused);
+    let response = builder.build_no_records(header);
+    response_handle
+        .send_response(response)
+        .await
+        .unwrap_or_else(|_| ResponseInfo::from(*request.header()))
+}
+
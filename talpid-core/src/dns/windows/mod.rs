@@ -3,11 +3,22 @@ use std::{
     io,
     net::IpAddr,
     process::{Command, Stdio},
+    ptr,
+    time::Duration,
 };
 use talpid_types::ErrorExt;
-use winapi::shared::guiddef::GUID;
+use winapi::{
+    shared::guiddef::GUID,
+    um::{
+        handleapi::CloseHandle,
+        synchapi::{CreateEventW, ResetEvent, WaitForMultipleObjects},
+        winbase::WAIT_OBJECT_0,
+        winnt::{HANDLE, KEY_NOTIFY, REG_NOTIFY_CHANGE_LAST_SET, REG_NOTIFY_CHANGE_NAME},
+        winreg::RegNotifyChangeKeyValue,
+    },
+};
 use winreg::{
-    enums::{HKEY_LOCAL_MACHINE, KEY_SET_VALUE},
+    enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_SET_VALUE},
     transaction::Transaction,
     RegKey,
 };
@@ -39,40 +50,317 @@ pub enum Error {
     /// Failed to locate system dir.
     #[error(display = "Failed to locate the system directory")]
     SystemDirError(#[error(source)] io::Error),
+
+    /// Failed to start or reconfigure the stub resolver.
+    #[error(display = "Failed to start the stub resolver")]
+    StubResolverError(#[error(source)] super::resolver::Error),
+
+    /// Failed to set up a registry change notification.
+    #[error(display = "Failed to watch registry key for changes")]
+    WatchRegistryError(#[error(source)] io::Error),
+}
+
+/// DNS-over-HTTPS configuration for an interface, written to the Windows 11
+/// `DohInterfaceSettings` registry keys.
+#[derive(Debug, Clone)]
+pub struct DohConfig {
+    /// The DoH template URL to use, e.g. `https://dns.mullvad.net/dns-query`.
+    pub template: String,
+    /// Whether DoH is required (autoupgrade disabled, plaintext fallback refused).
+    pub require_doh: bool,
+}
+
+// `DohFlags` bit requiring DoH for the server - see `DNS_SERVER_DOH_FLAGS` in `windns.h`.
+const DNS_SERVER_DOH_REQUIRE_FLAG: u32 = 0x1;
+// `DohFlags` bit that simply allows opportunistic upgrade to DoH for the server.
+const DNS_SERVER_DOH_FLAG: u32 = 0x2;
+
+const NRPT_POLICY_PATH: &str =
+    r#"SOFTWARE\Policies\Microsoft\Windows NT\DNSClient\DnsPolicyConfig"#;
+// Marks the rule as enabled and naming a specific set of DNS servers to use - see
+// `NRPT_RULE_CONFIG_OPTIONS` in `nrptpolicy.h`.
+const NRPT_RULE_CONFIG_OPTIONS: u32 = 0x8;
+
+/// A domain that split DNS should route to the tunnel's nameservers. Every domain gets an NRPT
+/// rule regardless of `routing_only`; no domain is added to the interface's search list.
+#[derive(Debug, Clone)]
+pub struct DnsDomain {
+    pub name: String,
+    pub routing_only: bool,
 }
 
 pub struct DnsMonitor {
+    current_alias: Option<String>,
     current_guid: Option<GUID>,
+    current_servers: Vec<IpAddr>,
+    current_domains: Vec<DnsDomain>,
+    stub_resolver: Option<super::resolver::ResolverHandle>,
+    nrpt_rules: Vec<String>,
+    ipv6_first: bool,
 }
 
 impl super::DnsMonitorT for DnsMonitor {
     type Error = Error;
 
     fn new() -> Result<Self, Error> {
-        Ok(DnsMonitor { current_guid: None })
+        Ok(DnsMonitor {
+            current_alias: None,
+            current_guid: None,
+            current_servers: vec![],
+            current_domains: vec![],
+            stub_resolver: None,
+            nrpt_rules: vec![],
+            ipv6_first: false,
+        })
     }
 
     fn set(&mut self, interface: &str, servers: &[IpAddr]) -> Result<(), Error> {
         let guid = guid_from_luid(&luid_from_alias(interface).map_err(Error::InterfaceLuidError)?)
             .map_err(Error::InterfaceGuidError)?;
-        set_dns(&guid, servers)?;
+
+        let stub_addrs = self.start_or_update_stub_resolver(servers.to_vec())?;
+
+        let rules = set_dns(&guid, &stub_addrs, None, &self.nrpt_rules, &[])?;
+        self.current_alias = Some(interface.to_string());
         self.current_guid = Some(guid);
+        self.current_servers = stub_addrs;
+        self.current_domains = vec![];
+        self.nrpt_rules = rules;
         flush_dns_cache()?;
         Ok(())
     }
 
     fn reset(&mut self) -> Result<(), Error> {
+        self.stub_resolver = None;
+        self.current_servers.clear();
+        self.current_domains.clear();
+        self.current_alias = None;
         if let Some(guid) = self.current_guid.take() {
-            return set_dns(&guid, &[]).and(flush_dns_cache());
+            set_dns(&guid, &[], None, &self.nrpt_rules, &[])?;
+            self.nrpt_rules.clear();
+            return flush_dns_cache();
+        }
+        Ok(())
+    }
+}
+
+impl DnsMonitor {
+    /// Sets the stub resolver's lookup strategy: `true` prefers AAAA records and only falls
+    /// back to A (`Ipv6thenIpv4`), `false` requests both families (`Ipv4AndIpv6`). Takes effect
+    /// the next time the stub resolver is started; changing the upstreams of a running stub
+    /// resolver keeps the strategy it was started with.
+    pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
+        self.ipv6_first = ipv6_first;
+    }
+
+    /// Like [`DnsMonitorT::set`], but also configures DNS-over-HTTPS for the interface.
+    pub fn set_with_doh(
+        &mut self,
+        interface: &str,
+        servers: &[IpAddr],
+        doh: Option<&DohConfig>,
+    ) -> Result<(), Error> {
+        let guid = guid_from_luid(&luid_from_alias(interface).map_err(Error::InterfaceLuidError)?)
+            .map_err(Error::InterfaceGuidError)?;
+
+        let stub_addrs = self.start_or_update_stub_resolver(servers.to_vec())?;
+
+        let rules = set_dns(&guid, &stub_addrs, doh, &self.nrpt_rules, &[])?;
+        self.current_alias = Some(interface.to_string());
+        self.current_guid = Some(guid);
+        self.current_servers = stub_addrs;
+        self.current_domains = vec![];
+        self.nrpt_rules = rules;
+        flush_dns_cache()?;
+        Ok(())
+    }
+
+    /// Like [`DnsMonitorT::set`], but restricts which domains are resolved via the tunnel
+    /// (split DNS) by creating NRPT rules for each of `domains` instead of a catch-all
+    /// `NameServer` override.
+    pub fn set_with_split_dns(
+        &mut self,
+        interface: &str,
+        servers: &[IpAddr],
+        domains: &[DnsDomain],
+    ) -> Result<(), Error> {
+        let guid = guid_from_luid(&luid_from_alias(interface).map_err(Error::InterfaceLuidError)?)
+            .map_err(Error::InterfaceGuidError)?;
+
+        let stub_addrs = self.start_or_update_stub_resolver(servers.to_vec())?;
+
+        let rules = set_dns(&guid, &stub_addrs, None, &self.nrpt_rules, domains)?;
+        self.current_alias = Some(interface.to_string());
+        self.current_guid = Some(guid);
+        self.current_servers = stub_addrs;
+        self.current_domains = domains.to_vec();
+        self.nrpt_rules = rules;
+        flush_dns_cache()?;
+        Ok(())
+    }
+
+    /// Watches the registry for changes to the DNS servers or LLMNR setting of the current
+    /// interface and the parent `Interfaces` keys (to catch adapter re-creation), re-applying
+    /// our configuration whenever they diverge from what we last set. Blocks the calling thread
+    /// until `should_continue` returns `false`, so this is meant to be run on its own background
+    /// thread, mirroring `SystemdResolved::watch_dns_changes` on Linux.
+    pub fn watch_dns_changes<F: FnMut() + Send + 'static, S: Fn() -> bool>(
+        &mut self,
+        mut on_change: F,
+        should_continue: S,
+    ) -> Result<(), Error> {
+        let alias = match self.current_alias.clone() {
+            Some(alias) => alias,
+            None => return Ok(()),
+        };
+        let mut guid = match self.current_guid {
+            Some(guid) => guid,
+            None => return Ok(()),
+        };
+        let mut guid_str = string_from_guid(&guid);
+        let mut watchers = build_watchers(&guid_str).map_err(Error::WatchRegistryError)?;
+
+        while should_continue() {
+            let events = watchers
+                .iter()
+                .map(|(_, watcher)| watcher.event)
+                .collect::<Vec<_>>();
+            if wait_any(&events, Duration::from_millis(500)).is_none() {
+                continue;
+            }
+
+            // The `Interfaces` parent keys are watched precisely so we notice the adapter being
+            // torn down and recreated with a new GUID - re-resolve it from the alias every time
+            // instead of trusting the GUID captured when watching started.
+            let resolved_guid = luid_from_alias(&alias)
+                .map_err(Error::InterfaceLuidError)
+                .and_then(|luid| guid_from_luid(&luid).map_err(Error::InterfaceGuidError));
+
+            match resolved_guid {
+                Ok(new_guid) => {
+                    let new_guid_str = string_from_guid(&new_guid);
+                    if new_guid_str != guid_str {
+                        log::info!(
+                            "Tunnel interface was recreated with a new GUID - re-registering DNS watchers"
+                        );
+                        guid = new_guid;
+                        guid_str = new_guid_str;
+                        self.current_guid = Some(guid);
+                        watchers = build_watchers(&guid_str).map_err(Error::WatchRegistryError)?;
+                    }
+                }
+                Err(error) => {
+                    log::warn!(
+                        "{}",
+                        error.display_chain_with_msg(
+                            "Failed to re-resolve the tunnel interface while watching DNS changes"
+                        )
+                    );
+                    continue;
+                }
+            }
+
+            if self.enforce_dns(&guid, &guid_str)? {
+                on_change();
+            }
+
+            for (_, watcher) in &mut watchers {
+                watcher.rearm().map_err(Error::WatchRegistryError)?;
+            }
         }
+
         Ok(())
     }
+
+    /// Re-applies our DNS configuration if the registry no longer reflects `current_servers`
+    /// (not compared in split-DNS mode - the interface's `NameServer` is deliberately left
+    /// untouched in that mode, so it isn't something to compare against), or if LLMNR has been
+    /// turned back on. Returns whether a change was detected and corrected.
+    fn enforce_dns(&mut self, guid: &GUID, guid_str: &str) -> Result<bool, Error> {
+        // In split-DNS mode, `config_interface` never touches `NameServer` - it's whatever the
+        // host had configured (static or DHCP) and not ours to compare against.
+        let dns_matches = if self.current_domains.is_empty() {
+            let expected_v4 = self
+                .current_servers
+                .iter()
+                .filter(|addr| addr.is_ipv4())
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            let expected_v6 = self
+                .current_servers
+                .iter()
+                .filter(|addr| addr.is_ipv6())
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+
+            current_name_server_value("Tcpip", guid_str) == expected_v4
+                && current_name_server_value("Tcpip6", guid_str) == expected_v6
+        } else {
+            true
+        };
+
+        let v4_multicast_disabled = current_enable_multicast_value("Tcpip", guid_str) == Some(0);
+        let v6_multicast_disabled = current_enable_multicast_value("Tcpip6", guid_str) == Some(0);
+
+        if dns_matches && v4_multicast_disabled && v6_multicast_disabled {
+            return Ok(false);
+        }
+
+        log::warn!("DNS settings were changed behind our back - reapplying");
+        let rules = set_dns(
+            guid,
+            &self.current_servers,
+            None,
+            &self.nrpt_rules,
+            &self.current_domains,
+        )?;
+        self.nrpt_rules = rules;
+        flush_dns_cache()?;
+        Ok(true)
+    }
+
+    /// Starts the loopback stub resolver the first time this is called, pointing it at
+    /// `upstreams`, or simply swaps the upstreams on later calls (e.g. on relay switch). Returns
+    /// the loopback addresses the OS should be pointed at instead of `upstreams`.
+    fn start_or_update_stub_resolver(
+        &mut self,
+        upstreams: Vec<IpAddr>,
+    ) -> Result<Vec<IpAddr>, Error> {
+        match &self.stub_resolver {
+            Some(resolver) => {
+                resolver
+                    .set_upstreams(upstreams)
+                    .map_err(Error::StubResolverError)?;
+                Ok(resolver.listen_addrs().to_vec())
+            }
+            None => {
+                let resolver = tokio::runtime::Handle::current()
+                    .block_on(super::resolver::start(upstreams, self.ipv6_first))
+                    .map_err(Error::StubResolverError)?;
+                let addrs = resolver.listen_addrs().to_vec();
+                self.stub_resolver = Some(resolver);
+                Ok(addrs)
+            }
+        }
+    }
 }
 
-fn set_dns(interface: &GUID, servers: &[IpAddr]) -> Result<(), Error> {
+/// Configures DNS for `interface`, tearing down `old_nrpt_rules` and (if `domains` is
+/// non-empty) creating new NRPT rules in their place. Returns the GUIDs of the rules that were
+/// created, so the caller can tear them down on the next call.
+fn set_dns(
+    interface: &GUID,
+    servers: &[IpAddr],
+    doh: Option<&DohConfig>,
+    old_nrpt_rules: &[String],
+    domains: &[DnsDomain],
+) -> Result<Vec<String>, Error> {
     let transaction = Transaction::new().map_err(Error::SetResolversError)?;
-    let result = match set_dns_inner(&transaction, interface, servers) {
-        Ok(()) => transaction.commit(),
+    let result = set_dns_inner(&transaction, interface, servers, doh, old_nrpt_rules, domains);
+    let result = match result {
+        Ok(rules) => transaction.commit().map(|()| rules),
         Err(error) => transaction.rollback().and(Err(error)),
     };
     result.map_err(Error::SetResolversError)
@@ -82,35 +370,169 @@ fn set_dns_inner(
     transaction: &Transaction,
     interface: &GUID,
     servers: &[IpAddr],
-) -> io::Result<()> {
+    doh: Option<&DohConfig>,
+    old_nrpt_rules: &[String],
+    domains: &[DnsDomain],
+) -> io::Result<Vec<String>> {
     let guid_str = string_from_guid(interface);
 
+    // when using split DNS, only the domains covered by NRPT rules should be routed to us - skip
+    // touching the interface's own `NameServer` entirely so the rest keep using the host's
+    // existing (static or DHCP-assigned) resolvers instead of having them wiped.
+    let interface_servers: Option<&[IpAddr]> = if domains.is_empty() { Some(servers) } else { None };
+
     config_interface(
         transaction,
         &guid_str,
         "Tcpip",
-        servers.iter().filter(|addr| addr.is_ipv4()),
+        interface_servers.map(|servers| servers.iter().filter(|addr| addr.is_ipv4())),
     )?;
 
     config_interface(
         transaction,
         &guid_str,
         "Tcpip6",
-        servers.iter().filter(|addr| addr.is_ipv6()),
+        interface_servers.map(|servers| servers.iter().filter(|addr| addr.is_ipv6())),
     )?;
 
+    config_doh(transaction, &guid_str, "Doh", doh)?;
+    config_doh(transaction, &guid_str, "Doh6", doh)?;
+
+    remove_nrpt_rules(transaction, old_nrpt_rules)?;
+    let new_rules = create_nrpt_rules(transaction, servers, domains)?;
+
+    Ok(new_rules)
+}
+
+/// Creates an NRPT rule under `DnsPolicyConfig` for each of `domains`, pointing it at `servers`.
+fn create_nrpt_rules(
+    transaction: &Transaction,
+    servers: &[IpAddr],
+    domains: &[DnsDomain],
+) -> io::Result<Vec<String>> {
+    let server_list = servers
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let mut rule_guids = Vec::with_capacity(domains.len());
+    for domain in domains {
+        let rule_guid = nrpt_rule_guid(&domain.name);
+        let reg_path = format!(r#"{NRPT_POLICY_PATH}\{rule_guid}"#);
+        let (rule_key, _) =
+            RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey_transacted(&reg_path, transaction)?;
+
+        rule_key.set_value("Name", &domain.name)?;
+        rule_key.set_value("GenericDNSServers", &server_list)?;
+        rule_key.set_value("ConfigOptions", &NRPT_RULE_CONFIG_OPTIONS)?;
+
+        rule_guids.push(rule_guid);
+    }
+    Ok(rule_guids)
+}
+
+fn remove_nrpt_rules(transaction: &Transaction, rule_guids: &[String]) -> io::Result<()> {
+    for rule_guid in rule_guids {
+        let reg_path = format!(r#"{NRPT_POLICY_PATH}\{rule_guid}"#);
+        match RegKey::predef(HKEY_LOCAL_MACHINE).delete_subkey_transacted(&reg_path, transaction) {
+            Ok(()) => (),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Derives a stable pseudo-GUID for an NRPT rule from the routed domain name, so re-applying
+/// the same split-DNS configuration tears down and recreates the same rule rather than leaking
+/// a new one on every call.
+fn nrpt_rule_guid(domain: &str) -> String {
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    let mut hasher = DefaultHasher::new();
+    domain.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    format!(
+        "{{{:08x}-{:04x}-{:04x}-{:04x}-{:012x}}}",
+        (hash >> 32) as u32,
+        (hash >> 16) as u16,
+        hash as u16,
+        (hash >> 48) as u16,
+        hash & 0xffff_ffff_ffff,
+    )
+}
+
+/// Writes (or removes) the `DohInterfaceSettings\{Doh,Doh6}\{guid}` registry value that tells
+/// Windows 11 to upgrade DNS queries on this interface to DNS-over-HTTPS.
+fn config_doh(
+    transaction: &Transaction,
+    guid: &str,
+    subkey: &str,
+    doh: Option<&DohConfig>,
+) -> io::Result<()> {
+    let reg_path = format!(
+        r#"SYSTEM\CurrentControlSet\Services\Dnscache\Parameters\DohInterfaceSettings\{subkey}\{guid}"#,
+    );
+
+    match doh {
+        Some(doh) => {
+            let (adapter_key, _) = RegKey::predef(HKEY_LOCAL_MACHINE)
+                .create_subkey_transacted(reg_path, transaction)?;
+            let flags = if doh.require_doh {
+                DNS_SERVER_DOH_REQUIRE_FLAG
+            } else {
+                DNS_SERVER_DOH_FLAG
+            };
+            adapter_key.set_value("DohFlags", &flags)?;
+            adapter_key.set_value("DohTemplate", &doh.template)?;
+        }
+        None => {
+            match RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey_transacted_with_flags(
+                &reg_path,
+                transaction,
+                KEY_SET_VALUE,
+            ) {
+                Ok(adapter_key) => {
+                    adapter_key.delete_value("DohFlags").or_else(ignore_not_found)?;
+                    adapter_key.delete_value("DohTemplate").or_else(ignore_not_found)?;
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
     Ok(())
 }
 
+fn ignore_not_found(error: io::Error) -> io::Result<()> {
+    if error.kind() == io::ErrorKind::NotFound {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Configures the `NameServer` value for `service`'s interface, and always tries to disable
+/// LLMNR on it. `nameservers: None` means "leave `NameServer` untouched" (used for split DNS, so
+/// the host's own static/DHCP-assigned resolvers are left in place); `Some` sets it to the given
+/// servers, or deletes it (falling back to DHCP/automatic) if the iterator is empty.
 fn config_interface<'a>(
     transaction: &Transaction,
     guid: &str,
     service: &str,
-    nameservers: impl Iterator<Item = &'a IpAddr>,
+    nameservers: Option<impl Iterator<Item = &'a IpAddr>>,
 ) -> io::Result<()> {
-    let nameservers = nameservers
-        .map(|addr| addr.to_string())
-        .collect::<Vec<String>>();
+    let nameservers = nameservers.map(|nameservers| {
+        nameservers
+            .map(|addr| addr.to_string())
+            .collect::<Vec<String>>()
+    });
 
     let reg_path =
         format!(r#"SYSTEM\CurrentControlSet\Services\{service}\Parameters\Interfaces\{guid}"#,);
@@ -121,23 +543,29 @@ fn config_interface<'a>(
     ) {
         Ok(adapter_key) => Ok(adapter_key),
         Err(error) => {
-            if nameservers.is_empty() && error.kind() == io::ErrorKind::NotFound {
+            if nameservers.as_ref().map_or(true, |ns| ns.is_empty())
+                && error.kind() == io::ErrorKind::NotFound
+            {
                 return Ok(());
             }
             Err(error)
         }
     }?;
 
-    if !nameservers.is_empty() {
-        adapter_key.set_value("NameServer", &nameservers.join(","))?;
-    } else {
-        adapter_key.delete_value("NameServer").or_else(|error| {
-            if error.kind() == io::ErrorKind::NotFound {
-                Ok(())
-            } else {
-                Err(error)
-            }
-        })?;
+    match nameservers {
+        Some(nameservers) if !nameservers.is_empty() => {
+            adapter_key.set_value("NameServer", &nameservers.join(","))?;
+        }
+        Some(_) => {
+            adapter_key.delete_value("NameServer").or_else(|error| {
+                if error.kind() == io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(error)
+                }
+            })?;
+        }
+        None => (),
     }
 
     // Try to disable LLMNR on the interface
@@ -151,6 +579,144 @@ fn config_interface<'a>(
     Ok(())
 }
 
+/// Sets up watchers for the DNS-relevant registry keys of the interface identified by
+/// `guid_str`, plus the `Interfaces` parent keys (to catch the interface being recreated).
+fn build_watchers(guid_str: &str) -> io::Result<Vec<(&'static str, RegistryWatcher)>> {
+    let watches = [
+        ("Tcpip", watch_path("Tcpip", guid_str), true),
+        ("Tcpip6", watch_path("Tcpip6", guid_str), true),
+        ("Tcpip", interfaces_path("Tcpip"), false),
+        ("Tcpip6", interfaces_path("Tcpip6"), false),
+    ];
+
+    watches
+        .iter()
+        .map(|(service, path, watch_value)| {
+            RegistryWatcher::new(path, *watch_value).map(|watcher| (*service, watcher))
+        })
+        .collect()
+}
+
+fn interfaces_path(service: &str) -> String {
+    format!(r#"SYSTEM\CurrentControlSet\Services\{service}\Parameters\Interfaces"#)
+}
+
+fn watch_path(service: &str, guid: &str) -> String {
+    format!("{}\\{}", interfaces_path(service), guid)
+}
+
+fn current_name_server_value(service: &str, guid: &str) -> String {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey_with_flags(watch_path(service, guid), KEY_READ)
+        .and_then(|key| key.get_value("NameServer"))
+        .unwrap_or_default()
+}
+
+fn current_enable_multicast_value(service: &str, guid: &str) -> Option<u32> {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey_with_flags(watch_path(service, guid), KEY_READ)
+        .and_then(|key| key.get_value("EnableMulticast"))
+        .ok()
+}
+
+/// Wraps a registry key opened for `RegNotifyChangeKeyValue` notifications, backed by a
+/// manual-reset event that is signaled once whenever the key (or, for the `Interfaces` parent
+/// keys, one of its subkeys) changes.
+struct RegistryWatcher {
+    key: RegKey,
+    event: HANDLE,
+    watch_values: bool,
+}
+
+// The event handle is only ever accessed through the Windows API, which is safe to call from
+// any thread.
+unsafe impl Send for RegistryWatcher {}
+
+impl RegistryWatcher {
+    fn new(path: &str, watch_values: bool) -> io::Result<Self> {
+        let key = match RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey_with_flags(path, KEY_NOTIFY | KEY_READ)
+        {
+            Ok(key) => key,
+            // The key may not exist yet (e.g. `Tcpip6` before the interface has an IPv6
+            // configuration) - that's fine, we still want to watch the parent for its creation.
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey_with_flags(
+                    interfaces_parent(path),
+                    KEY_NOTIFY | KEY_READ,
+                )?
+            }
+            Err(error) => return Err(error),
+        };
+
+        let event = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        let watcher = RegistryWatcher {
+            key,
+            event,
+            watch_values,
+        };
+        watcher.arm()?;
+        Ok(watcher)
+    }
+
+    fn arm(&self) -> io::Result<()> {
+        let filter = if self.watch_values {
+            REG_NOTIFY_CHANGE_LAST_SET | REG_NOTIFY_CHANGE_NAME
+        } else {
+            REG_NOTIFY_CHANGE_NAME
+        };
+        let status = unsafe {
+            RegNotifyChangeKeyValue(self.key.raw_handle(), 1, filter, self.event, 1)
+        };
+        if status != 0 {
+            return Err(io::Error::from_raw_os_error(status));
+        }
+        Ok(())
+    }
+
+    /// Re-arms the notification after it has fired once (`RegNotifyChangeKeyValue` only
+    /// signals a single time per call). The manual-reset event is cleared first, so it is only
+    /// signaled again by a new change.
+    fn rearm(&mut self) -> io::Result<()> {
+        if unsafe { ResetEvent(self.event) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.arm()
+    }
+
+}
+
+/// Waits up to `timeout` for any one of `events` to be signaled, returning its index, or `None`
+/// on timeout. Used instead of polling each watcher's event in sequence, which would multiply
+/// the wait by the number of watchers in the worst case.
+fn wait_any(events: &[HANDLE], timeout: Duration) -> Option<usize> {
+    let millis = timeout.as_millis().min(u32::MAX as u128) as u32;
+    let status = unsafe {
+        WaitForMultipleObjects(events.len() as u32, events.as_ptr(), 0, millis)
+    };
+    if status >= WAIT_OBJECT_0 && (status - WAIT_OBJECT_0) < events.len() as u32 {
+        Some((status - WAIT_OBJECT_0) as usize)
+    } else {
+        None
+    }
+}
+
+impl Drop for RegistryWatcher {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.event) };
+    }
+}
+
+fn interfaces_parent(path: &str) -> String {
+    path.rsplit_once('\\')
+        .map(|(parent, _)| parent.to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
 fn flush_dns_cache() -> Result<(), Error> {
     let sysdir = get_system_dir().map_err(Error::SystemDirError)?;
     Command::new(sysdir.join("ipconfig.exe"))
@@ -0,0 +1,166 @@
+//! A small parser/writer for `/etc/resolv.conf`, used by [`crate::systemd_resolved`] to
+//! preserve the host's existing search domains and options when we have to take over DNS on
+//! systems that aren't using systemd-resolved.
+
+use std::net::IpAddr;
+
+/// Maximum number of search domains honored by the resolver, matching glibc's `MAXDNSRCH`.
+const MAX_SEARCH_DOMAINS: usize = 6;
+/// Maximum combined length, in characters, of the domains on a `search` line, matching glibc.
+const MAX_SEARCH_LIST_LEN: usize = 256;
+
+/// The pieces of a `resolv.conf` file that we care about preserving.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    pub options: Vec<String>,
+}
+
+impl ResolvConf {
+    /// Parses the contents of a `resolv.conf` file, extracting `nameserver`, `search`/`domain`
+    /// and `options` entries. Unrecognized lines and comments are ignored.
+    pub fn parse(contents: &str) -> Self {
+        let mut resolv_conf = ResolvConf::default();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let keyword = match parts.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+
+            match keyword {
+                "nameserver" => {
+                    if let Some(address) = parts.next().and_then(|addr| addr.parse().ok()) {
+                        resolv_conf.nameservers.push(address);
+                    }
+                }
+                // `domain` sets a single search domain; a later `search` line overrides it, as
+                // on a real resolver.
+                "domain" => {
+                    resolv_conf.search_domains = parts.next().into_iter().map(String::from).collect();
+                }
+                "search" => {
+                    resolv_conf.search_domains = parts.map(String::from).collect();
+                }
+                "options" => {
+                    resolv_conf.options.extend(parts.map(String::from));
+                }
+                _ => (),
+            }
+        }
+
+        resolv_conf
+    }
+
+    /// Renders a `resolv.conf` using `nameservers` in place of whatever this file had, while
+    /// preserving the parsed search domains and options. The search domain list is capped to
+    /// the limits glibc's resolver itself enforces (at most [`MAX_SEARCH_DOMAINS`] domains,
+    /// and at most [`MAX_SEARCH_LIST_LEN`] characters total), emitting a comment noting the
+    /// truncation on its own line when that happens.
+    pub fn render(&self, nameservers: &[IpAddr]) -> String {
+        let mut output = String::new();
+
+        for nameserver in nameservers {
+            output.push_str(&format!("nameserver {}\n", nameserver));
+        }
+
+        if !self.search_domains.is_empty() {
+            let (search_domains, truncated) = truncate_search_domains(&self.search_domains);
+            if truncated {
+                output.push_str("# search domain list truncated to fit resolver limits\n");
+            }
+            if !search_domains.is_empty() {
+                output.push_str(&format!("search {}\n", search_domains.join(" ")));
+            }
+        }
+
+        if !self.options.is_empty() {
+            output.push_str(&format!("options {}\n", self.options.join(" ")));
+        }
+
+        output
+    }
+}
+
+/// Caps `domains` to at most [`MAX_SEARCH_DOMAINS`] entries, and then to at most
+/// [`MAX_SEARCH_LIST_LEN`] combined characters (counting the single space between each
+/// domain). Returns the capped list and whether anything was dropped.
+fn truncate_search_domains(domains: &[String]) -> (Vec<String>, bool) {
+    let mut truncated = domains.len() > MAX_SEARCH_DOMAINS;
+    let mut kept = domains.iter().take(MAX_SEARCH_DOMAINS).cloned().collect::<Vec<_>>();
+
+    let mut total_len = kept.iter().map(String::len).sum::<usize>() + kept.len().saturating_sub(1);
+    while total_len > MAX_SEARCH_LIST_LEN {
+        kept.pop();
+        truncated = true;
+        total_len = kept.iter().map(String::len).sum::<usize>() + kept.len().saturating_sub(1);
+    }
+
+    (kept, truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nameservers_search_and_options() {
+        let contents = "\
+nameserver 192.168.1.1
+nameserver fe80::1
+search lan example.com
+options ndots:2 timeout:1 attempts:3 edns0
+";
+        let parsed = ResolvConf::parse(contents);
+        assert_eq!(
+            parsed.nameservers,
+            vec![
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert_eq!(parsed.search_domains, vec!["lan", "example.com"]);
+        assert_eq!(
+            parsed.options,
+            vec!["ndots:2", "timeout:1", "attempts:3", "edns0"]
+        );
+    }
+
+    #[test]
+    fn search_overrides_domain() {
+        let contents = "domain example.com\nsearch lan corp.example.com\n";
+        let parsed = ResolvConf::parse(contents);
+        assert_eq!(parsed.search_domains, vec!["lan", "corp.example.com"]);
+    }
+
+    #[test]
+    fn render_substitutes_nameservers_and_keeps_search_and_options() {
+        let mut parsed = ResolvConf::default();
+        parsed.search_domains = vec!["lan".to_string()];
+        parsed.options = vec!["ndots:2".to_string()];
+
+        let rendered = parsed.render(&["10.64.0.1".parse().unwrap()]);
+        assert_eq!(rendered, "nameserver 10.64.0.1\nsearch lan\noptions ndots:2\n");
+    }
+
+    #[test]
+    fn render_truncates_too_many_search_domains() {
+        let mut parsed = ResolvConf::default();
+        parsed.search_domains = (0..10).map(|i| format!("domain{}.example.com", i)).collect();
+
+        let rendered = parsed.render(&[]);
+        assert!(rendered.contains("# search domain list truncated"));
+        let search_line = rendered
+            .lines()
+            .find(|line| line.starts_with("search "))
+            .unwrap();
+        assert!(search_line.split_whitespace().count() - 1 <= MAX_SEARCH_DOMAINS);
+    }
+}
@@ -7,6 +7,7 @@ use dbus::{
     },
     message::{MatchRule, SignalArgs},
 };
+use crate::resolv_conf::ResolvConf;
 use lazy_static::lazy_static;
 use libc::{AF_INET, AF_INET6};
 use std::{fs, io, net::IpAddr, path::Path, sync::Arc, time::Duration};
@@ -23,6 +24,9 @@ pub enum Error {
     #[error(display = "Failed to read /etc/resolv.conf: _0")]
     ReadResolvConfError(#[error(source)] io::Error),
 
+    #[error(display = "Failed to write /etc/resolv.conf: _0")]
+    WriteResolvConfError(#[error(source)] io::Error),
+
     #[error(display = "/etc/resolv.conf contents do not match systemd-resolved resolv.conf")]
     ResolvConfDiffers,
 
@@ -52,6 +56,78 @@ pub enum Error {
 
     #[error(display = "Failed to remove a match for DNS config updates")]
     DnsUpdateRemoveMatchError(#[error(source)] dbus::Error),
+
+    #[error(display = "Failed to configure DNS-over-TLS")]
+    SetDnsOverTlsError(#[error(source)] dbus::Error),
+
+    #[error(display = "Failed to configure DNSSEC")]
+    SetDnssecError(#[error(source)] dbus::Error),
+}
+
+/// DNSSEC validation mode, as understood by `resolved`'s `SetLinkDNSSEC` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnssecMode {
+    Yes,
+    No,
+    AllowDowngrade,
+}
+
+impl DnssecMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            DnssecMode::Yes => "yes",
+            DnssecMode::No => "no",
+            DnssecMode::AllowDowngrade => "allow-downgrade",
+        }
+    }
+}
+
+impl Default for DnssecMode {
+    fn default() -> Self {
+        DnssecMode::No
+    }
+}
+
+/// DNS-over-TLS enforcement mode, as understood by `resolved`'s `SetLinkDNSOverTLS` method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsOverTlsMode {
+    /// Require DNS-over-TLS; queries fail if the servers can't be reached over TLS.
+    Yes,
+    /// Don't use DNS-over-TLS.
+    No,
+    /// Use DNS-over-TLS when possible, but fall back to plaintext rather than breaking
+    /// resolution if the servers don't support it - the mode best suited to a VPN client,
+    /// which shouldn't lose connectivity just because DoT is blocked on a given network.
+    Opportunistic,
+}
+
+impl DnsOverTlsMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            DnsOverTlsMode::Yes => "yes",
+            DnsOverTlsMode::No => "no",
+            DnsOverTlsMode::Opportunistic => "opportunistic",
+        }
+    }
+}
+
+impl Default for DnsOverTlsMode {
+    fn default() -> Self {
+        DnsOverTlsMode::No
+    }
+}
+
+/// Configuration for DNS-over-TLS and DNSSEC enforcement on a link managed by
+/// systemd-resolved.
+#[derive(Debug, Clone, Default)]
+pub struct EncryptedDnsConfig {
+    /// DNS-over-TLS enforcement mode to request for the configured servers.
+    pub dot: DnsOverTlsMode,
+    /// The certificate name to expect from the DoT resolver, if it differs from
+    /// the server address itself.
+    pub server_name: Option<String>,
+    /// DNSSEC validation mode to enforce on the link.
+    pub dnssec: DnssecMode,
 }
 
 lazy_static! {
@@ -76,9 +152,14 @@ const MANAGER_INTERFACE: &str = "org.freedesktop.resolve1.Manager";
 const DNS_SERVERS: &str = "DNS";
 const GET_LINK_METHOD: &str = "GetLink";
 const SET_DNS_METHOD: &str = "SetDNS";
+const SET_DNS_EX_METHOD: &str = "SetLinkDNSEx";
+const SET_DNS_OVER_TLS_METHOD: &str = "SetLinkDNSOverTLS";
+const SET_DNSSEC_METHOD: &str = "SetLinkDNSSEC";
 const SET_DOMAINS_METHOD: &str = "SetDomains";
 const REVERT_METHOD: &str = "Revert";
 
+const UNKNOWN_METHOD_ERROR: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+
 #[derive(Clone)]
 pub struct SystemdResolved {
     pub dbus_connection: Arc<SyncConnection>,
@@ -88,6 +169,32 @@ pub struct DnsState {
     pub interface_path: dbus::Path<'static>,
     pub interface_index: u32,
     pub set_servers: Vec<IpAddr>,
+    pub domains: Vec<DnsDomain>,
+}
+
+/// A routing domain passed to `resolved`'s `SetLinkDomains` method. If `routing_only` is set,
+/// the domain is only used to decide which link a query should be routed to - the link's
+/// regular search domains are left untouched.
+#[derive(Debug, Clone)]
+pub struct DnsDomain {
+    pub name: String,
+    pub routing_only: bool,
+}
+
+impl DnsDomain {
+    pub fn routing(name: impl Into<String>) -> Self {
+        DnsDomain {
+            name: name.into(),
+            routing_only: true,
+        }
+    }
+
+    pub fn search(name: impl Into<String>) -> Self {
+        DnsDomain {
+            name: name.into(),
+            routing_only: false,
+        }
+    }
 }
 
 impl SystemdResolved {
@@ -141,15 +248,42 @@ impl SystemdResolved {
     fn ensure_resolvconf_contents() -> Result<()> {
         let resolv_conf =
             fs::read_to_string(RESOLV_CONF_PATH).map_err(Error::ReadResolvConfError)?;
-        if RESOLVED_STUB_PATHS
+
+        let stub_contents = RESOLVED_STUB_PATHS
             .iter()
-            .filter_map(|path| fs::read_to_string(path).ok())
-            .any(|link_contents| link_contents == resolv_conf)
-        {
-            Ok(())
-        } else {
-            Err(Error::ResolvConfDiffers)
+            .find_map(|path| fs::read_to_string(path).ok());
+
+        if let Some(ref stub_contents) = stub_contents {
+            if *stub_contents == resolv_conf {
+                return Ok(());
+            }
         }
+
+        // /etc/resolv.conf isn't already the resolved stub, and doesn't need to be a symlink to
+        // it either - we just need resolved's stub servers to be consulted. Write our own
+        // resolv.conf pointing at the stub's nameservers, preserving whatever search domains and
+        // options the host had configured so short-name resolution and resolver tuning keep
+        // working.
+        let stub = stub_contents
+            .as_deref()
+            .map(ResolvConf::parse)
+            .filter(|stub| !stub.nameservers.is_empty())
+            .ok_or(Error::ResolvConfDiffers)?;
+
+        let mut preserved = ResolvConf::parse(&resolv_conf);
+        preserved.nameservers = stub.nameservers.clone();
+
+        fs::write(RESOLV_CONF_PATH, preserved.render(&stub.nameservers))
+            .map_err(Error::WriteResolvConfError)
+    }
+
+    /// Returns the search domains configured in the host's `/etc/resolv.conf`, so they can be
+    /// registered as real search suffixes with `resolved` instead of being lost when we take
+    /// over the link's domains.
+    fn host_search_domains() -> Vec<String> {
+        fs::read_to_string(RESOLV_CONF_PATH)
+            .map(|contents| ResolvConf::parse(&contents).search_domains)
+            .unwrap_or_default()
     }
 
     fn path_is_resolvconf_stub(link_path: &Path) -> bool {
@@ -219,17 +353,46 @@ impl SystemdResolved {
     }
 
     pub fn set_dns(&self, interface_index: u32, servers: &[IpAddr]) -> Result<DnsState> {
+        self.set_dns_with_encryption(interface_index, servers, &EncryptedDnsConfig::default())
+    }
+
+    pub fn set_dns_with_encryption(
+        &self,
+        interface_index: u32,
+        servers: &[IpAddr],
+        encrypted_dns: &EncryptedDnsConfig,
+    ) -> Result<DnsState> {
+        self.set_dns_with_domains(interface_index, servers, encrypted_dns, &[])
+    }
+
+    /// Like [`Self::set_dns_with_encryption`], but additionally lets the caller restrict which
+    /// domains are resolved via this link (split DNS). If `domains` is empty, all queries are
+    /// routed through the link, as before.
+    pub fn set_dns_with_domains(
+        &self,
+        interface_index: u32,
+        servers: &[IpAddr],
+        encrypted_dns: &EncryptedDnsConfig,
+        domains: &[DnsDomain],
+    ) -> Result<DnsState> {
         let link_object_path = self
             .fetch_link(interface_index)
             .map_err(|e| Error::GetLinkError(Box::new(e)))?;
 
         let mut set_servers = servers.to_vec();
         set_servers.sort();
-        self.set_link_dns(&link_object_path, servers)?;
+        self.set_link_dns(
+            interface_index,
+            &link_object_path,
+            servers,
+            encrypted_dns,
+            domains,
+        )?;
         Ok(DnsState {
             interface_path: link_object_path,
             interface_index,
             set_servers,
+            domains: domains.to_vec(),
         })
     }
 
@@ -246,20 +409,36 @@ impl SystemdResolved {
 
     fn set_link_dns<'a, 'b: 'a>(
         &'a self,
+        interface_index: u32,
         link_object_path: &'b dbus::Path<'static>,
         servers: &[IpAddr],
+        encrypted_dns: &EncryptedDnsConfig,
+        domains: &[DnsDomain],
     ) -> Result<()> {
-        let servers = servers
-            .iter()
-            .map(|addr| (ip_version(addr), ip_to_bytes(addr)))
-            .collect::<Vec<_>>();
-        self.as_link_object(link_object_path.clone())
-            .method_call(LINK_INTERFACE, SET_DNS_METHOD, (servers,))
-            .map_err(Error::DBusRpcError)?;
-
-        // set the search domain to catch all DNS requests, forces the link to be the prefered
-        // resolver, otherwise systemd-resolved will use other interfaces to do DNS lookups
-        let dns_domains: &[_] = &[(&".", true)];
+        self.set_link_dns_servers(interface_index, link_object_path, servers, encrypted_dns)?;
+
+        // Always push the current desired value, even when it's "off" - the link object
+        // persists across calls (e.g. a settings change while already connected), so only
+        // setting DoT/DNSSEC when enabled would leave a link stuck enforcing whatever was last
+        // explicitly requested instead of backing out when the caller disables it.
+        self.set_link_dns_over_tls(link_object_path, encrypted_dns.dot)?;
+        self.set_link_dnssec(link_object_path, encrypted_dns.dnssec)?;
+
+        // with no explicit domains, fall back to the catch-all "." domain, forcing the link to
+        // be the preferred resolver for everything - otherwise systemd-resolved will use other
+        // interfaces to do DNS lookups. Register the host's own search domains alongside it so
+        // short-name resolution on the tunnel still honors them, instead of losing them.
+        let host_search_domains = Self::host_search_domains();
+        let owned_domains: Vec<(&str, bool)> = if domains.is_empty() {
+            std::iter::once((".", true))
+                .chain(host_search_domains.iter().map(|domain| (domain.as_str(), false)))
+                .collect()
+        } else {
+            domains
+                .iter()
+                .map(|domain| (domain.name.as_str(), domain.routing_only))
+                .collect()
+        };
 
         Proxy::new(
             RESOLVED_BUS,
@@ -267,10 +446,102 @@ impl SystemdResolved {
             RPC_TIMEOUT,
             &*self.dbus_connection,
         )
-        .method_call(LINK_INTERFACE, SET_DOMAINS_METHOD, (dns_domains,))
+        .method_call(LINK_INTERFACE, SET_DOMAINS_METHOD, (owned_domains,))
         .map_err(Error::SetDomainsError)
     }
 
+    /// Configures the DNS servers for a link, preferring the encrypted-DNS capable
+    /// `SetLinkDNSEx` method and falling back to the plaintext `SetDNS` method if the running
+    /// `resolved` is too old to expose it.
+    fn set_link_dns_servers(
+        &self,
+        interface_index: u32,
+        link_object_path: &dbus::Path<'static>,
+        servers: &[IpAddr],
+        encrypted_dns: &EncryptedDnsConfig,
+    ) -> Result<()> {
+        let servers_ex = servers
+            .iter()
+            .map(|addr| {
+                (
+                    ip_version(addr),
+                    ip_to_bytes(addr),
+                    if encrypted_dns.dot != DnsOverTlsMode::No { 853u16 } else { 0u16 },
+                    encrypted_dns
+                        .server_name
+                        .clone()
+                        .unwrap_or_else(String::new),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let result = self.as_link_object(link_object_path.clone()).method_call(
+            LINK_INTERFACE,
+            SET_DNS_EX_METHOD,
+            (interface_index as i32, servers_ex),
+        );
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(error) if error.name() == Some(UNKNOWN_METHOD_ERROR) => {
+                log::warn!(
+                    "resolved does not expose {}, falling back to plaintext {} - \
+                     encrypted DNS will not be enforced",
+                    SET_DNS_EX_METHOD,
+                    SET_DNS_METHOD
+                );
+                let servers = servers
+                    .iter()
+                    .map(|addr| (ip_version(addr), ip_to_bytes(addr)))
+                    .collect::<Vec<_>>();
+                self.as_link_object(link_object_path.clone())
+                    .method_call(LINK_INTERFACE, SET_DNS_METHOD, (servers,))
+                    .map_err(Error::DBusRpcError)
+            }
+            Err(error) => Err(Error::DBusRpcError(error)),
+        }
+    }
+
+    fn set_link_dns_over_tls(
+        &self,
+        link_object_path: &dbus::Path<'static>,
+        mode: DnsOverTlsMode,
+    ) -> Result<()> {
+        match self.as_link_object(link_object_path.clone()).method_call(
+            LINK_INTERFACE,
+            SET_DNS_OVER_TLS_METHOD,
+            (mode.as_str(),),
+        ) {
+            Ok(()) => Ok(()),
+            Err(error) if error.name() == Some(UNKNOWN_METHOD_ERROR) => {
+                log::warn!(
+                    "resolved does not expose {}, DNS-over-TLS will not be enforced",
+                    SET_DNS_OVER_TLS_METHOD
+                );
+                Ok(())
+            }
+            Err(error) => Err(Error::SetDnsOverTlsError(error)),
+        }
+    }
+
+    fn set_link_dnssec(&self, link_object_path: &dbus::Path<'static>, mode: DnssecMode) -> Result<()> {
+        match self.as_link_object(link_object_path.clone()).method_call(
+            LINK_INTERFACE,
+            SET_DNSSEC_METHOD,
+            (mode.as_str(),),
+        ) {
+            Ok(()) => Ok(()),
+            Err(error) if error.name() == Some(UNKNOWN_METHOD_ERROR) => {
+                log::warn!(
+                    "resolved does not expose {}, DNSSEC will not be enforced",
+                    SET_DNSSEC_METHOD
+                );
+                Ok(())
+            }
+            Err(error) => Err(Error::SetDnssecError(error)),
+        }
+    }
+
     pub fn revert_link(&mut self, dns_state: DnsState) -> std::result::Result<(), dbus::Error> {
         let link = self.as_link_object(dns_state.interface_path);
 